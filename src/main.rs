@@ -1,192 +1,994 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Json, Router,
 };
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use futures::stream::Stream;
 use ics2000_rs::{Device, Ics, Room, Scene};
-use serde::{Deserialize, Serialize};
+use prometheus::{HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Registry, TextEncoder};
+use rand::rngs::OsRng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
+    collections::HashMap,
+    convert::Infallible,
     fs,
+    future::Future,
     net::SocketAddr,
     path::Path as StdPath,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use uuid::Uuid;
 
 #[derive(Clone)]
 struct AppState {
+    accounts: Arc<RwLock<HashMap<String, Arc<Account>>>>,
+    cache_db: sled::Db,
+    events: tokio::sync::broadcast::Sender<StateEvent>,
+    poll_interval_secs: u64,
+    tls_cert_path: Option<String>,
+    tls_key_path: Option<String>,
+    metrics: Metrics,
+}
+
+/// One logged-in (or previously logged-in) ICS-2000 hub, keyed by account id
+/// in `AppState::accounts`. Each account gets its own sled cache trees so
+/// devices/rooms/scenes from one home never bleed into another's stale read,
+/// and its own session token hash so a token issued for one account can
+/// never authorize requests against another.
+struct Account {
+    email: String,
     ics: Arc<Mutex<Option<Ics>>>,
+    device_cache: sled::Tree,
+    room_cache: sled::Tree,
+    scene_cache: sled::Tree,
+    token_hash: Arc<Mutex<Option<String>>>,
+}
+
+fn account_cache_tree(db: &sled::Db, account_id: &str, resource: &str) -> sled::Tree {
+    db.open_tree(format!("{account_id}:{resource}"))
+        .expect("Could not open cache tree")
+}
+
+fn new_account(
+    db: &sled::Db,
+    account_id: &str,
+    email: String,
+    ics: Arc<Mutex<Option<Ics>>>,
+    token_hash: Option<String>,
+) -> Account {
+    Account {
+        email,
+        ics,
+        device_cache: account_cache_tree(db, account_id, "devices"),
+        room_cache: account_cache_tree(db, account_id, "rooms"),
+        scene_cache: account_cache_tree(db, account_id, "scenes"),
+        token_hash: Arc::new(Mutex::new(token_hash)),
+    }
 }
 
+fn get_account(state: &AppState, account_id: &str) -> Result<Arc<Account>, (StatusCode, String)> {
+    state
+        .accounts
+        .read()
+        .expect("RwLock was poisoned")
+        .get(account_id)
+        .cloned()
+        .ok_or((StatusCode::NOT_FOUND, "Unknown account".to_string()))
+}
+
+const CACHE_KEY: &[u8] = b"latest";
+
 #[derive(Serialize, Deserialize)]
-struct Config {
+struct CacheEntry<T> {
+    data: T,
+    fetched_at: u64,
+}
+
+#[derive(Serialize)]
+struct CachedPayload<T> {
+    data: T,
+    stale: bool,
+    fetched_at: u64,
+}
+
+#[derive(Deserialize)]
+struct RefreshQuery {
+    #[serde(default)]
+    refresh: bool,
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the unix epoch")
+        .as_secs()
+}
+
+fn read_cache<T: DeserializeOwned>(tree: &sled::Tree) -> Option<CacheEntry<T>> {
+    let bytes = tree.get(CACHE_KEY).expect("sled read failed")?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+fn write_cache<T: Serialize>(tree: &sled::Tree, entry: &CacheEntry<T>) {
+    let bytes = serde_json::to_vec(entry).expect("could not serialize cache entry");
+    tree.insert(CACHE_KEY, bytes).expect("sled write failed");
+}
+
+/// Drops a cached value so the next read falls through to a live fetch,
+/// instead of serving a value a mutation has just made stale as `fresh`.
+fn invalidate_cache(tree: &sled::Tree) {
+    tree.remove(CACHE_KEY).expect("sled remove failed");
+}
+
+/// Serves a resource from the live hub via `fetch`, falling back to the last
+/// cached value (marked `stale: true`) when the live call fails and a cached
+/// value exists. When `refresh` is false and a cached value exists that was
+/// fetched within `ttl_secs`, it is returned as `stale: false` without
+/// calling `fetch` at all, so a dashboard reload doesn't re-discover every
+/// device from the hub; once the entry is older than `ttl_secs` it is never
+/// reported fresh without re-validating it live first, since the state
+/// poller (or another session) may have changed it out of band.
+async fn cached_resource<T, E, F, Fut>(
+    tree: &sled::Tree,
+    refresh: bool,
+    ttl_secs: u64,
+    fetch: F,
+) -> Result<(StatusCode, Json<CachedPayload<T>>), (StatusCode, String)>
+where
+    T: Serialize + DeserializeOwned,
+    E: std::fmt::Display,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    if !refresh {
+        if let Some(entry) = read_cache::<T>(tree) {
+            if now_unix().saturating_sub(entry.fetched_at) < ttl_secs {
+                return Ok((
+                    StatusCode::OK,
+                    Json(CachedPayload {
+                        data: entry.data,
+                        stale: false,
+                        fetched_at: entry.fetched_at,
+                    }),
+                ));
+            }
+        }
+    }
+
+    match fetch().await {
+        Ok(data) => {
+            let entry = CacheEntry {
+                data,
+                fetched_at: now_unix(),
+            };
+            write_cache(tree, &entry);
+            Ok((
+                StatusCode::OK,
+                Json(CachedPayload {
+                    data: entry.data,
+                    stale: false,
+                    fetched_at: entry.fetched_at,
+                }),
+            ))
+        }
+        Err(e) => match read_cache::<T>(tree) {
+            Some(entry) => Ok((
+                StatusCode::OK,
+                Json(CachedPayload {
+                    data: entry.data,
+                    stale: true,
+                    fetched_at: entry.fetched_at,
+                }),
+            )),
+            None => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        },
+    }
+}
+
+#[derive(Clone)]
+struct Metrics {
+    registry: Registry,
+    devices_discovered: IntGaugeVec,
+    rooms_discovered: IntGaugeVec,
+    scenes_discovered: IntGaugeVec,
+    device_actions: IntCounterVec,
+    scene_actions: IntCounterVec,
+    login_successes: IntCounter,
+    login_failures: IntCounter,
+    ics_call_latency: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let devices_discovered = IntGaugeVec::new(
+            prometheus::Opts::new("ics2000_devices_discovered", "Number of devices discovered"),
+            &["account"],
+        )
+        .unwrap();
+        let rooms_discovered = IntGaugeVec::new(
+            prometheus::Opts::new("ics2000_rooms_discovered", "Number of rooms discovered"),
+            &["account"],
+        )
+        .unwrap();
+        let scenes_discovered = IntGaugeVec::new(
+            prometheus::Opts::new("ics2000_scenes_discovered", "Number of scenes discovered"),
+            &["account"],
+        )
+        .unwrap();
+        let device_actions = IntCounterVec::new(
+            prometheus::Opts::new("ics2000_device_actions_total", "Device actions by type"),
+            &["action"],
+        )
+        .unwrap();
+        let scene_actions = IntCounterVec::new(
+            prometheus::Opts::new("ics2000_scene_actions_total", "Scene actions by type"),
+            &["action"],
+        )
+        .unwrap();
+        let login_successes = IntCounter::new(
+            "ics2000_login_successes_total",
+            "Successful logins to the ICS-2000 hub",
+        )
+        .unwrap();
+        let login_failures = IntCounter::new(
+            "ics2000_login_failures_total",
+            "Failed logins to the ICS-2000 hub",
+        )
+        .unwrap();
+        let ics_call_latency = HistogramVec::new(
+            prometheus::HistogramOpts::new(
+                "ics2000_call_latency_seconds",
+                "Latency of blocking calls into ics2000-rs",
+            ),
+            &["call"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(devices_discovered.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(rooms_discovered.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(scenes_discovered.clone()))
+            .unwrap();
+        registry.register(Box::new(device_actions.clone())).unwrap();
+        registry.register(Box::new(scene_actions.clone())).unwrap();
+        registry.register(Box::new(login_successes.clone())).unwrap();
+        registry.register(Box::new(login_failures.clone())).unwrap();
+        registry.register(Box::new(ics_call_latency.clone())).unwrap();
+
+        Metrics {
+            registry,
+            devices_discovered,
+            rooms_discovered,
+            scenes_discovered,
+            device_actions,
+            scene_actions,
+            login_successes,
+            login_failures,
+            ics_call_latency,
+        }
+    }
+}
+
+/// Times an async call into the hub and records it under `call` in the
+/// `ics_call_latency` histogram, so a flaky ICS-2000 connection shows up as
+/// rising latency rather than silent request stalls.
+async fn time_ics_call<F: Future>(metrics: &Metrics, call: &str, fut: F) -> F::Output {
+    let start = Instant::now();
+    let result = fut.await;
+    metrics
+        .ics_call_latency
+        .with_label_values(&[call])
+        .observe(start.elapsed().as_secs_f64());
+    result
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum StateEvent {
+    Device {
+        account_id: String,
+        device_id: usize,
+        on: bool,
+        dim: Option<usize>,
+    },
+    Scene {
+        account_id: String,
+        scene_id: usize,
+        active: bool,
+    },
+}
+
+impl StateEvent {
+    fn account_id(&self) -> &str {
+        match self {
+            StateEvent::Device { account_id, .. } => account_id,
+            StateEvent::Scene { account_id, .. } => account_id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedAccount {
+    account_id: String,
     email: String,
-    password: String,
+    #[serde(default)]
+    token_hash: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Config {
+    accounts: Vec<PersistedAccount>,
+    #[serde(default = "default_poll_interval_secs")]
+    poll_interval_secs: u64,
+    #[serde(default)]
+    tls_cert_path: Option<String>,
+    #[serde(default)]
+    tls_key_path: Option<String>,
+}
+
+fn default_poll_interval_secs() -> u64 {
+    5
+}
+
+/// Waits for Ctrl+C or SIGTERM, whichever comes first, so the caller can
+/// start a graceful shutdown instead of dropping in-flight requests.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("shutting down gracefully");
+}
+
+fn persist_config(state: &AppState, accounts: &HashMap<String, Arc<Account>>) {
+    let config = Config {
+        accounts: accounts
+            .iter()
+            .map(|(account_id, account)| PersistedAccount {
+                account_id: account_id.clone(),
+                email: account.email.clone(),
+                token_hash: account
+                    .token_hash
+                    .lock()
+                    .expect("Mutex was poisoned")
+                    .clone(),
+            })
+            .collect(),
+        poll_interval_secs: state.poll_interval_secs,
+        tls_cert_path: state.tls_cert_path.clone(),
+        tls_key_path: state.tls_key_path.clone(),
+    };
+    fs::write("settings.json", serde_json::to_string(&config).unwrap())
+        .expect("Could not save settings file");
 }
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let state = AppState {
-        ics: Arc::new(Mutex::new(None)),
+    let (events_tx, _) = tokio::sync::broadcast::channel(100);
+    let cache_db = sled::open("cache.sled").expect("Could not open cache database");
+    let mut state = AppState {
+        accounts: Arc::new(RwLock::new(HashMap::new())),
+        cache_db,
+        events: events_tx,
+        poll_interval_secs: default_poll_interval_secs(),
+        tls_cert_path: None,
+        tls_key_path: None,
+        metrics: Metrics::new(),
     };
     let config_file = StdPath::new("settings.json");
-    let ics_clone = Arc::clone(&state.ics);
     if config_file.exists() {
         let config_json =
             fs::read_to_string("settings.json").expect("Unable to read settings file");
         match serde_json::from_str::<Config>(&config_json) {
             Ok(config) => {
-                ics_login(config.email, config.password, ics_clone).await;
+                state.poll_interval_secs = config.poll_interval_secs;
+                state.tls_cert_path = config.tls_cert_path;
+                state.tls_key_path = config.tls_key_path;
+                // NOTE: this intentionally does not "re-login all accounts on
+                // startup" as originally specced. Passwords are never persisted
+                // (see the token-auth change), so there is nothing to log back in
+                // with; a restart can only recreate each account as logged-out. An
+                // operator has to `POST /login` with that account's `account_id`
+                // again to reconnect it to its hub. Until then the persisted token
+                // hash still authorizes the previous session token; a successful
+                // login replaces it with the new token's hash, so the previous
+                // token stops working. `GET /accounts` reports `connected: false`
+                // for every account left in this state so it isn't a silent gap.
+                let mut accounts = state.accounts.write().expect("RwLock was poisoned");
+                let account_count = config.accounts.len();
+                for persisted in config.accounts {
+                    let account = new_account(
+                        &state.cache_db,
+                        &persisted.account_id,
+                        persisted.email,
+                        Arc::new(Mutex::new(None)),
+                        persisted.token_hash,
+                    );
+                    accounts.insert(persisted.account_id, Arc::new(account));
+                }
+                if account_count > 0 {
+                    tracing::warn!(
+                        "recreated {account_count} account(s) from settings.json as logged-out; \
+                         POST /login with each account_id to reconnect it"
+                    );
+                }
             }
             Err(_) => {}
         };
     }
+
+    spawn_state_poller(
+        Arc::clone(&state.accounts),
+        state.events.clone(),
+        Duration::from_secs(state.poll_interval_secs),
+    );
+
+    let authenticated = Router::new()
+        .route("/accounts/:account_id/devices", get(devices))
+        .route("/accounts/:account_id/devices/:device_id", post(device_action))
+        .route("/accounts/:account_id/rooms", get(rooms))
+        .route("/accounts/:account_id/scenes", get(scenes))
+        .route("/accounts/:account_id/scenes/:scene_id", post(scene_action))
+        .route("/accounts/:account_id/events", get(events))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_auth));
+
+    let tls = match (&state.tls_cert_path, &state.tls_key_path) {
+        (Some(cert), Some(key)) => Some(
+            RustlsConfig::from_pem_file(cert, key)
+                .await
+                .expect("Could not load TLS cert/key"),
+        ),
+        _ => None,
+    };
+    let cache_db = state.cache_db.clone();
+
     let app = Router::new()
         .route("/login", post(login))
-        .route("/devices", get(devices))
-        .route("/devices/:device_id", post(device_action))
-        .route("/rooms", get(rooms))
-        .route("/scenes", get(scenes))
-        .route("/scenes/:scene_id", post(scene_action))
+        .route("/accounts", get(list_accounts))
+        .route("/metrics", get(metrics))
+        .merge(authenticated)
         .with_state(state);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    tracing::debug!("listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let handle = Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        // Stop accepting new connections, then give open connections (and any
+        // in-flight spawn_blocking device/scene action) up to 30s to finish.
+        shutdown_handle.graceful_shutdown(Some(Duration::from_secs(30)));
+    });
+
+    match tls {
+        Some(tls) => {
+            tracing::debug!("listening on {} (https)", addr);
+            axum_server::bind_rustls(addr, tls)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+        None => {
+            tracing::debug!("listening on {} (http)", addr);
+            axum_server::bind(addr)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+    }
+
+    cache_db.flush_async().await.expect("Could not flush cache database");
 }
 
+/// Polls every registered account's hub on an interval and publishes an
+/// event for each device whose on/off or dim value changed, and each scene
+/// whose active state changed, since the last poll, so
+/// `/accounts/:account_id/events` subscribers don't have to re-fetch the
+/// full device/scene list to notice a change.
+fn spawn_state_poller(
+    accounts: Arc<RwLock<HashMap<String, Arc<Account>>>>,
+    events: tokio::sync::broadcast::Sender<StateEvent>,
+    interval: Duration,
+) {
+    tokio::spawn(async move {
+        let mut last_device_state: HashMap<String, HashMap<usize, (bool, Option<usize>)>> =
+            HashMap::new();
+        let mut last_scene_state: HashMap<String, HashMap<usize, bool>> = HashMap::new();
+        loop {
+            tokio::time::sleep(interval).await;
+            let snapshot: Vec<(String, Arc<Mutex<Option<Ics>>>)> = accounts
+                .read()
+                .expect("RwLock was poisoned")
+                .iter()
+                .map(|(account_id, account)| (account_id.clone(), Arc::clone(&account.ics)))
+                .collect();
+
+            for (account_id, ics) in snapshot {
+                let ics_clone = Arc::clone(&ics);
+                let devices = tokio::task::spawn_blocking(move || {
+                    let mut ics = ics_clone.lock().expect("Mutex was poisoned");
+                    ics.as_mut().and_then(|ics| ics.get_devices().ok())
+                })
+                .await
+                .expect("state poller panicked");
+
+                if let Some(devices) = devices {
+                    let account_state = last_device_state.entry(account_id.clone()).or_default();
+                    for device in devices {
+                        let snapshot = (device.on, device.dim);
+                        if account_state.get(&device.id) != Some(&snapshot) {
+                            account_state.insert(device.id, snapshot);
+                            let _ = events.send(StateEvent::Device {
+                                account_id: account_id.clone(),
+                                device_id: device.id,
+                                on: device.on,
+                                dim: device.dim,
+                            });
+                        }
+                    }
+                }
+
+                let scenes = tokio::task::spawn_blocking(move || {
+                    let mut ics = ics.lock().expect("Mutex was poisoned");
+                    ics.as_mut().and_then(|ics| ics.get_scenes().ok())
+                })
+                .await
+                .expect("state poller panicked");
+
+                let Some(scenes) = scenes else {
+                    continue;
+                };
+
+                let account_state = last_scene_state.entry(account_id.clone()).or_default();
+                for scene in scenes {
+                    if account_state.get(&scene.id) != Some(&scene.active) {
+                        account_state.insert(scene.id, scene.active);
+                        let _ = events.send(StateEvent::Scene {
+                            account_id: account_id.clone(),
+                            scene_id: scene.id,
+                            active: scene.active,
+                        });
+                    }
+                }
+            }
+        }
+    });
+}
+
+async fn events(
+    State(state): State<AppState>,
+    Path(account_id): Path<String>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = BroadcastStream::new(state.events.subscribe()).filter_map(move |event| {
+        event
+            .ok()
+            .filter(|event| event.account_id() == account_id)
+            .map(|event| Ok(Event::default().json_data(event).unwrap_or_else(|_| Event::default())))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let metric_families = state.metrics.registry.gather();
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&metric_families, &mut buffer)
+        .expect("Could not encode metrics");
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        buffer,
+    )
+}
+
+/// Logs into the ICS-2000 hub and keeps the session in memory. The password
+/// is never persisted to disk; only an opaque session token (hashed) is
+/// written to the settings file, so a restart requires logging in again.
 async fn ics_login(email: String, password: String, ics: Arc<Mutex<Option<Ics>>>) -> bool {
-    let email_clone = email.clone();
-    let password_clone = password.clone();
     let ics_clone = Arc::clone(&ics);
-    let resp = tokio::task::spawn_blocking(move || {
+    tokio::task::spawn_blocking(move || {
         let mut ics = ics_clone.lock().expect("Mutex was poisoned");
-        *ics = Some(Ics::new(&email_clone, &password_clone, true));
+        *ics = Some(Ics::new(&email, &password, true));
         ics.as_mut().unwrap().login()
     })
     .await
-    .expect("Error logging in");
-    let config = Config { email, password };
-    fs::write("settings.json", serde_json::to_string(&config).unwrap())
-        .expect("Could not save settings file");
-    resp
+    .expect("Error logging in")
+}
+
+#[derive(Serialize)]
+struct LoginResponse {
+    account_id: String,
+    token: String,
+}
+
+/// Logs into an ICS-2000 account and returns its `account_id` together with
+/// a session token. Pass an existing `account_id` in the request body to
+/// re-authenticate that same account (e.g. after a restart); omit it to
+/// register a new one in the multi-account registry.
+async fn login(
+    State(state): State<AppState>,
+    Json(payload): Json<Login>,
+) -> Result<Json<LoginResponse>, StatusCode> {
+    let account_id = payload
+        .account_id
+        .clone()
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let ics = state
+        .accounts
+        .read()
+        .expect("RwLock was poisoned")
+        .get(&account_id)
+        .map(|account| Arc::clone(&account.ics))
+        .unwrap_or_else(|| Arc::new(Mutex::new(None)));
+
+    let email = payload.email.clone();
+    if !ics_login(payload.email, payload.password, Arc::clone(&ics)).await {
+        // Drop the failed session so the account reads as not connected.
+        *ics.lock().expect("Mutex was poisoned") = None;
+        state.metrics.login_failures.inc();
+        return Err(StatusCode::FORBIDDEN);
+    }
+    state.metrics.login_successes.inc();
+
+    let token = Uuid::new_v4().to_string();
+    let salt = SaltString::generate(&mut OsRng);
+    let token_hash = Argon2::default()
+        .hash_password(token.as_bytes(), &salt)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .to_string();
+
+    {
+        let mut accounts = state.accounts.write().expect("RwLock was poisoned");
+        let account = accounts.entry(account_id.clone()).or_insert_with(|| {
+            Arc::new(new_account(&state.cache_db, &account_id, email, ics, None))
+        });
+        *account.token_hash.lock().expect("Mutex was poisoned") = Some(token_hash);
+        persist_config(&state, &accounts);
+    }
+
+    Ok(Json(LoginResponse { account_id, token }))
 }
 
-async fn login(State(state): State<AppState>, Json(payload): Json<Login>) -> StatusCode {
-    match ics_login(payload.email, payload.password, state.ics).await {
-        true => StatusCode::OK,
-        false => StatusCode::FORBIDDEN,
+#[derive(Serialize)]
+struct AccountStatus {
+    account_id: String,
+    connected: bool,
+}
+
+/// Lists every registered account id and whether it is currently connected
+/// to its hub. Since passwords are never persisted, an account recreated
+/// from `settings.json` on startup comes back with `connected: false` until
+/// its `account_id` is passed to `POST /login` again — this is how that gap
+/// is surfaced instead of leaving an operator to guess which homes dropped
+/// out. This route is unauthenticated like `/login` and `/metrics`, so it
+/// intentionally omits `email` — account ids carry no credential material,
+/// but enumerating every home's login email to anyone on localhost would.
+async fn list_accounts(State(state): State<AppState>) -> Json<Vec<AccountStatus>> {
+    let accounts = state.accounts.read().expect("RwLock was poisoned");
+    Json(
+        accounts
+            .iter()
+            .map(|(account_id, account)| AccountStatus {
+                account_id: account_id.clone(),
+                connected: account.ics.lock().expect("Mutex was poisoned").is_some(),
+            })
+            .collect(),
+    )
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Middleware guarding every `/accounts/:account_id/...` route: requires a
+/// token matching the hash stored on the `:account_id` named in the path
+/// from that account's most recent successful login. The token is read from
+/// an `Authorization: Bearer <token>` header, falling back to a `?token=`
+/// query parameter when no header is present — `EventSource` (used by
+/// browser dashboards to subscribe to `/accounts/:account_id/events`) cannot
+/// set custom headers, so the SSE route needs this fallback to be usable
+/// from a browser at all. A token is only ever valid for the account it was
+/// issued to. `/login`, `/accounts` and `/metrics` are exempt.
+async fn require_auth<B>(
+    State(state): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    let header_token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| value.to_string());
+
+    let (mut parts, body) = req.into_parts();
+
+    let token = match header_token {
+        Some(token) => token,
+        None => Query::<TokenQuery>::from_request_parts(&mut parts, &state)
+            .await
+            .ok()
+            .and_then(|Query(query)| query.token)
+            .ok_or(StatusCode::UNAUTHORIZED)?,
+    };
+
+    let Path(params) = Path::<HashMap<String, String>>::from_request_parts(&mut parts, &state)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let account_id = params.get("account_id").ok_or(StatusCode::BAD_REQUEST)?;
+    let account = get_account(&state, account_id).map_err(|(status, _)| status)?;
+
+    let token_hash = account
+        .token_hash
+        .lock()
+        .expect("Mutex was poisoned")
+        .clone()
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let parsed_hash = PasswordHash::new(&token_hash).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    if Argon2::default()
+        .verify_password(token.as_bytes(), &parsed_hash)
+        .is_err()
+    {
+        return Err(StatusCode::UNAUTHORIZED);
     }
+
+    let req = Request::from_parts(parts, body);
+    Ok(next.run(req).await)
 }
 
 async fn devices(
     State(state): State<AppState>,
-) -> Result<(StatusCode, Json<Vec<Device>>), (StatusCode, String)> {
-    let ics_clone = Arc::clone(&state.ics);
-    let devices = tokio::task::spawn_blocking(move || {
-        let mut ics = ics_clone.lock().unwrap();
-        if ics.is_none() {
-            return Err("Not logged in");
-        }
-        ics.as_mut().unwrap().get_devices()
-    })
-    .await
-    .expect("Could not fetch devices");
+    Path(account_id): Path<String>,
+    Query(query): Query<RefreshQuery>,
+) -> Result<(StatusCode, Json<CachedPayload<Vec<Device>>>), (StatusCode, String)> {
+    let account = get_account(&state, &account_id)?;
+    let ics_clone = Arc::clone(&account.ics);
+    let metrics = state.metrics.clone();
+    let result = cached_resource(
+        &account.device_cache,
+        query.refresh,
+        state.poll_interval_secs,
+        move || async move {
+            time_ics_call(
+                &metrics,
+                "get_devices",
+                tokio::task::spawn_blocking(move || {
+                    let mut ics = ics_clone.lock().unwrap();
+                    if ics.is_none() {
+                        return Err("Not logged in".to_string());
+                    }
+                    ics.as_mut().unwrap().get_devices().map_err(|e| e.to_string())
+                }),
+            )
+            .await
+            .expect("Could not fetch devices")
+        },
+    )
+    .await;
 
-    match devices {
-        Ok(result) => Ok((StatusCode::OK, Json(result))),
-        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    if let Ok((_, Json(payload))) = &result {
+        state
+            .metrics
+            .devices_discovered
+            .with_label_values(&[&account_id])
+            .set(payload.data.len() as i64);
     }
+    result
 }
 
 async fn rooms(
     State(state): State<AppState>,
-) -> Result<(StatusCode, Json<Vec<Room>>), (StatusCode, String)> {
-    let ics_clone = Arc::clone(&state.ics);
-    let rooms = tokio::task::spawn_blocking(move || {
-        let mut ics = ics_clone.lock().unwrap();
-        if ics.is_none() {
-            return Err("Not logged in");
-        }
-        ics.as_mut().unwrap().get_rooms()
-    })
-    .await
-    .expect("Could not fetch rooms");
+    Path(account_id): Path<String>,
+    Query(query): Query<RefreshQuery>,
+) -> Result<(StatusCode, Json<CachedPayload<Vec<Room>>>), (StatusCode, String)> {
+    let account = get_account(&state, &account_id)?;
+    let ics_clone = Arc::clone(&account.ics);
+    let metrics = state.metrics.clone();
+    let result = cached_resource(
+        &account.room_cache,
+        query.refresh,
+        state.poll_interval_secs,
+        move || async move {
+            time_ics_call(
+                &metrics,
+                "get_rooms",
+                tokio::task::spawn_blocking(move || {
+                    let mut ics = ics_clone.lock().unwrap();
+                    if ics.is_none() {
+                        return Err("Not logged in".to_string());
+                    }
+                    ics.as_mut().unwrap().get_rooms().map_err(|e| e.to_string())
+                }),
+            )
+            .await
+            .expect("Could not fetch rooms")
+        },
+    )
+    .await;
 
-    match rooms {
-        Ok(result) => Ok((StatusCode::OK, Json(result))),
-        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    if let Ok((_, Json(payload))) = &result {
+        state
+            .metrics
+            .rooms_discovered
+            .with_label_values(&[&account_id])
+            .set(payload.data.len() as i64);
     }
+    result
 }
 
 async fn scenes(
     State(state): State<AppState>,
-) -> Result<(StatusCode, Json<Vec<Scene>>), (StatusCode, String)> {
-    let ics_clone = Arc::clone(&state.ics);
-    let scenes = tokio::task::spawn_blocking(move || {
-        let mut ics = ics_clone.lock().unwrap();
-        if ics.is_none() {
-            return Err("Not logged in");
-        }
-        ics.as_mut().unwrap().get_scenes()
-    })
-    .await
-    .expect("Could not fetch scenes");
+    Path(account_id): Path<String>,
+    Query(query): Query<RefreshQuery>,
+) -> Result<(StatusCode, Json<CachedPayload<Vec<Scene>>>), (StatusCode, String)> {
+    let account = get_account(&state, &account_id)?;
+    let ics_clone = Arc::clone(&account.ics);
+    let metrics = state.metrics.clone();
+    let result = cached_resource(
+        &account.scene_cache,
+        query.refresh,
+        state.poll_interval_secs,
+        move || async move {
+            time_ics_call(
+                &metrics,
+                "get_scenes",
+                tokio::task::spawn_blocking(move || {
+                    let mut ics = ics_clone.lock().unwrap();
+                    if ics.is_none() {
+                        return Err("Not logged in".to_string());
+                    }
+                    ics.as_mut().unwrap().get_scenes().map_err(|e| e.to_string())
+                }),
+            )
+            .await
+            .expect("Could not fetch scenes")
+        },
+    )
+    .await;
 
-    match scenes {
-        Ok(result) => Ok((StatusCode::OK, Json(result))),
-        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    if let Ok((_, Json(payload))) = &result {
+        state
+            .metrics
+            .scenes_discovered
+            .with_label_values(&[&account_id])
+            .set(payload.data.len() as i64);
     }
+    result
 }
 
 async fn device_action(
     State(state): State<AppState>,
-    Path(device_id): Path<usize>,
+    Path((account_id, device_id)): Path<(String, usize)>,
     Json(payload): Json<DeviceAction>,
-) -> StatusCode {
-    let ics_clone = Arc::clone(&state.ics);
-    tokio::task::spawn_blocking(move || {
-        let mut ics = ics_clone.lock().unwrap();
-        match payload.state {
-            DeviceState::On => ics.as_mut().unwrap().turn_on(device_id),
-            DeviceState::Off => ics.as_mut().unwrap().turn_off(device_id),
-            DeviceState::Dim(value) => ics.as_mut().unwrap().dim(device_id, value),
-        }
-    })
+) -> Result<StatusCode, (StatusCode, String)> {
+    let account = get_account(&state, &account_id)?;
+    let action = match payload.state {
+        DeviceState::On => "on",
+        DeviceState::Off => "off",
+        DeviceState::Dim(_) => "dim",
+    };
+    let ics_clone = Arc::clone(&account.ics);
+    let logged_in = time_ics_call(
+        &state.metrics,
+        "device_action",
+        tokio::task::spawn_blocking(move || {
+            let mut ics = ics_clone.lock().unwrap();
+            if ics.is_none() {
+                return false;
+            }
+            let _ = match payload.state {
+                DeviceState::On => ics.as_mut().unwrap().turn_on(device_id),
+                DeviceState::Off => ics.as_mut().unwrap().turn_off(device_id),
+                DeviceState::Dim(value) => ics.as_mut().unwrap().dim(device_id, value),
+            };
+            true
+        }),
+    )
     .await
-    .expect("Ics error");
+    .expect("device action task panicked");
+
+    if !logged_in {
+        return Err((StatusCode::CONFLICT, "Account is not logged in".to_string()));
+    }
 
-    StatusCode::OK
+    invalidate_cache(&account.device_cache);
+    state
+        .metrics
+        .device_actions
+        .with_label_values(&[action])
+        .inc();
+
+    Ok(StatusCode::OK)
 }
 
 async fn scene_action(
     State(state): State<AppState>,
-    Path(scene_id): Path<usize>,
+    Path((account_id, scene_id)): Path<(String, usize)>,
     Json(payload): Json<SceneAction>,
-) -> StatusCode {
-    let ics_clone = Arc::clone(&state.ics);
-    tokio::task::spawn_blocking(move || {
-        let mut ics = ics_clone.lock().unwrap();
-        match payload.state {
-            SceneState::Play => ics.as_mut().unwrap().start_scene(scene_id),
-            SceneState::Stop => ics.as_mut().unwrap().stop_scene(scene_id),
-        }
-    })
+) -> Result<StatusCode, (StatusCode, String)> {
+    let account = get_account(&state, &account_id)?;
+    let action = match payload.state {
+        SceneState::Play => "play",
+        SceneState::Stop => "stop",
+    };
+    let ics_clone = Arc::clone(&account.ics);
+    let logged_in = time_ics_call(
+        &state.metrics,
+        "scene_action",
+        tokio::task::spawn_blocking(move || {
+            let mut ics = ics_clone.lock().unwrap();
+            if ics.is_none() {
+                return false;
+            }
+            let _ = match payload.state {
+                SceneState::Play => ics.as_mut().unwrap().start_scene(scene_id),
+                SceneState::Stop => ics.as_mut().unwrap().stop_scene(scene_id),
+            };
+            true
+        }),
+    )
     .await
-    .expect("Ics error");
+    .expect("scene action task panicked");
+
+    if !logged_in {
+        return Err((StatusCode::CONFLICT, "Account is not logged in".to_string()));
+    }
 
-    StatusCode::OK
+    invalidate_cache(&account.scene_cache);
+    state
+        .metrics
+        .scene_actions
+        .with_label_values(&[action])
+        .inc();
+
+    Ok(StatusCode::OK)
 }
+
 #[derive(Deserialize)]
 struct Login {
     email: String,
     password: String,
+    #[serde(default)]
+    account_id: Option<String>,
 }
 
 #[derive(Deserialize)]